@@ -1,39 +1,329 @@
-use mio::net::{UnixListener, UnixStream};
+mod transport;
+
 use mio::{Events, Interest, Poll, Token};
+#[cfg(unix)]
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
 use serde::{Deserialize, Serialize};
-use std::fs::{remove_file, set_permissions, Permissions};
-use std::io::{self, Read};
+use std::collections::{HashMap, VecDeque};
+#[cfg(unix)]
+use std::io::{IoSlice, IoSliceMut};
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
 use std::time::Duration;
+use transport::{Listener, Stream};
 
 pub trait IpcServerCommand: Serialize + for<'a> Deserialize<'a> + std::fmt::Debug {
     type Response: Serialize + for<'a> Deserialize<'a> + std::fmt::Debug;
     type Context<'a>;
 
     fn process<'a, 'b>(self, context: &'b mut Self::Context<'a>) -> Self::Response;
+
+    /// Like `process`, but may push any number of responses through `sink`
+    /// instead of returning exactly one, for subscription-style commands.
+    /// The default implementation sends `process`'s single response.
+    fn process_stream<'a, 'b>(
+        self,
+        context: &'b mut Self::Context<'a>,
+        sink: &mut dyn ResponseSink<Self::Response>,
+    ) -> io::Result<()>
+    where
+        Self: Sized,
+    {
+        sink.send(self.process(context))
+    }
+
+    /// Like `process`, but also receives any file descriptors the client
+    /// attached via `client_send_with_fds`. Defaults to ignoring `fds` and
+    /// delegating to `process`. Unix only.
+    #[cfg(unix)]
+    fn process_with_fds<'a, 'b>(
+        self,
+        context: &'b mut Self::Context<'a>,
+        fds: Vec<OwnedFd>,
+    ) -> Self::Response
+    where
+        Self: Sized,
+    {
+        let _ = fds;
+        self.process(context)
+    }
+}
+
+/// A sink that `IpcServerCommand::process_stream` writes responses to. The
+/// server implements this over the client's connection, framing and
+/// writing each response as it is sent.
+pub trait ResponseSink<R> {
+    fn send(&mut self, response: R) -> io::Result<()>;
+
+    /// Signals that no further responses will follow for the command being
+    /// processed. Called automatically once `process_stream` returns. The
+    /// default implementation does nothing.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Wire-level wrapper distinguishing "here is a response" from "this
+/// command's stream of responses is finished".
+#[derive(Serialize, Deserialize, Debug)]
+enum StreamMessage<R> {
+    Item(R),
+    End,
+}
+
+struct StreamSink<'s> {
+    stream: &'s mut Stream,
+    /// Echoed back on every response so the client can match it to the
+    /// command that caused it, even if commands are pipelined.
+    request_id: u64,
+}
+
+impl<'s, R: Serialize + std::fmt::Debug> ResponseSink<R> for StreamSink<'s> {
+    fn send(&mut self, response: R) -> io::Result<()> {
+        let payload = encode_tagged(self.request_id, &StreamMessage::Item(response))?;
+        write_frame(self.stream, &payload)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let payload = encode_tagged(self.request_id, &StreamMessage::<R>::End)?;
+        write_frame(self.stream, &payload)
+    }
+}
+
+/// Default cap on a single frame's payload size (16 MiB). Guards against
+/// unbounded allocation if a length prefix is corrupted or forged.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+const LEN_PREFIX_SIZE: usize = 4;
+
+/// Accumulates bytes read from a stream into a complete length-prefixed
+/// frame, tolerating `WouldBlock` part-way through the length prefix or
+/// the body.
+#[derive(Debug)]
+struct FrameReader {
+    max_frame_size: u32,
+    len_buf: [u8; LEN_PREFIX_SIZE],
+    len_read: usize,
+    body: Vec<u8>,
+    body_len: Option<usize>,
+}
+
+impl FrameReader {
+    fn new(max_frame_size: u32) -> Self {
+        FrameReader {
+            max_frame_size,
+            len_buf: [0; LEN_PREFIX_SIZE],
+            len_read: 0,
+            body: Vec::new(),
+            body_len: None,
+        }
+    }
+
+    /// Reads as much of the current frame as is available on `stream`.
+    /// Returns `Ok(Some(payload))` once a full frame has arrived, or
+    /// `Ok(None)` if the stream would block first.
+    fn read_from<S: Read>(&mut self, stream: &mut S) -> io::Result<Option<Vec<u8>>> {
+        self.read_from_impl(|buf| stream.read(buf))
+    }
+
+    /// Like `read_from`, but reads via `recvmsg` on `fd` so any file
+    /// descriptors attached with an `SCM_RIGHTS` control message are
+    /// captured into `fds_out`. Unix only.
+    #[cfg(unix)]
+    fn read_from_fd(&mut self, fd: RawFd, fds_out: &mut Vec<OwnedFd>) -> io::Result<Option<Vec<u8>>> {
+        self.read_from_impl(|buf| recvmsg_with_fds(fd, buf, fds_out))
+    }
+
+    fn read_from_impl(
+        &mut self,
+        mut read: impl FnMut(&mut [u8]) -> io::Result<usize>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        if self.body_len.is_none() {
+            while self.len_read < LEN_PREFIX_SIZE {
+                match read(&mut self.len_buf[self.len_read..]) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed while reading frame length",
+                        ))
+                    }
+                    Ok(n) => self.len_read += n,
+                    Err(ref err) if would_block(err) => return Ok(None),
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let len = u32::from_le_bytes(self.len_buf);
+            if len > self.max_frame_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "frame length {} exceeds max frame size {}",
+                        len, self.max_frame_size
+                    ),
+                ));
+            }
+            self.body = Vec::with_capacity(len as usize);
+            self.body_len = Some(len as usize);
+        }
+
+        let body_len = self.body_len.unwrap();
+        let mut chunk = [0u8; 4096];
+        while self.body.len() < body_len {
+            let remaining = body_len - self.body.len();
+            let to_read = remaining.min(chunk.len());
+            match read(&mut chunk[..to_read]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed while reading frame body",
+                    ))
+                }
+                Ok(n) => self.body.extend_from_slice(&chunk[..n]),
+                Err(ref err) if would_block(err) => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.len_read = 0;
+        self.body_len = None;
+        Ok(Some(std::mem::take(&mut self.body)))
+    }
+}
+
+/// Reads one `recvmsg` worth of bytes from `fd` into `buf`, dup-ing any file
+/// descriptors received via an `SCM_RIGHTS` control message into
+/// `fds_out`. Rejects a message whose control data was truncated rather
+/// than silently dropping descriptors past the 16-fd cap. Unix only.
+#[cfg(unix)]
+fn recvmsg_with_fds(fd: RawFd, buf: &mut [u8], fds_out: &mut Vec<OwnedFd>) -> io::Result<usize> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; 16]);
+    let msg = recvmsg::<()>(fd, &mut iov, Some(&mut cmsg_buffer), MsgFlags::empty())
+        .map_err(|errno| io::Error::from_raw_os_error(errno as i32))?;
+
+    if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "control message truncated: more than 16 file descriptors attached to one message",
+        ));
+    }
+
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+            for raw_fd in raw_fds {
+                // Safety: the kernel just handed us ownership of this
+                // descriptor via the SCM_RIGHTS control message.
+                fds_out.push(unsafe { OwnedFd::from_raw_fd(raw_fd) });
+            }
+        }
+    }
+
+    Ok(msg.bytes)
+}
+
+/// Size in bytes of the request id prepended to every frame's payload.
+const REQUEST_ID_SIZE: usize = 8;
+
+/// Serializes `value` with a `u64` request id prepended, so the recipient
+/// can echo the id back without needing to understand `value`'s type.
+fn encode_tagged<T: Serialize>(request_id: u64, value: &T) -> io::Result<Vec<u8>> {
+    let body =
+        bincode::serialize(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut payload = Vec::with_capacity(REQUEST_ID_SIZE + body.len());
+    payload.extend_from_slice(&request_id.to_le_bytes());
+    payload.extend_from_slice(&body);
+    Ok(payload)
+}
+
+/// The inverse of `encode_tagged`: splits off the leading request id and
+/// deserializes the remainder as `T`.
+fn decode_tagged<T: for<'a> Deserialize<'a>>(payload: &[u8]) -> io::Result<(u64, T)> {
+    if payload.len() < REQUEST_ID_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame payload too short to contain a request id",
+        ));
+    }
+
+    let mut id_buf = [0u8; REQUEST_ID_SIZE];
+    id_buf.copy_from_slice(&payload[..REQUEST_ID_SIZE]);
+    let request_id = u64::from_le_bytes(id_buf);
+
+    let value = bincode::deserialize::<T>(&payload[REQUEST_ID_SIZE..])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((request_id, value))
+}
+
+/// Writes `payload` as a length-prefixed frame, retrying on `WouldBlock`
+/// until the whole frame has been written.
+fn write_frame<S: Write>(stream: &mut S, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(LEN_PREFIX_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+
+    let mut written = 0;
+    while written < frame.len() {
+        match stream.write(&frame[written..]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole frame",
+                ))
+            }
+            Ok(n) => written += n,
+            Err(ref err) if would_block(err) => {
+                // Spin loop is okay here.
+                // IPC server is not intended for large payloads or high volumes.
+                std::hint::spin_loop();
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+/// A live client connection, registered with the server's `Poll` under its
+/// own `Token` and holding whatever partial frame has been read from it so
+/// far.
+struct Connection {
+    stream: Stream,
+    reader: FrameReader,
+    /// File descriptors received for the frame currently being assembled,
+    /// via an `SCM_RIGHTS` control message. Unix only.
+    #[cfg(unix)]
+    received_fds: Vec<OwnedFd>,
 }
 
 pub struct IpcServer<C: IpcServerCommand> {
-    listener: UnixListener,
+    listener: Listener,
     poll: Poll,
     events: Events,
+    max_frame_size: u32,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
     _command: PhantomData<C>,
 }
 
 impl<C: IpcServerCommand> IpcServer<C> {
     /// Initialize a new IpcServer. Recall that there is no dedicated server
     /// thread. You must call `handle_new_messages` to poll for and process
-    /// new messages
-    pub fn new(socket_path: &str) -> io::Result<IpcServer<C>> {
-        if Path::new(socket_path).exists() {
-            remove_file(socket_path)?;
-        }
+    /// new messages.
+    ///
+    /// `address` is a filesystem path on Unix, and a `\\.\pipe\<name>`
+    /// named pipe name on Windows.
+    pub fn new(address: &str) -> io::Result<IpcServer<C>> {
+        Self::with_max_frame_size(address, DEFAULT_MAX_FRAME_SIZE)
+    }
 
-        let mut listener = UnixListener::bind(socket_path)?;
-        // Restrict permissions to owner read/write only
-        set_permissions(socket_path, Permissions::from_mode(0o600))?;
+    /// Like `new`, but rejects any incoming frame whose length prefix
+    /// exceeds `max_frame_size` instead of allocating unboundedly.
+    pub fn with_max_frame_size(address: &str, max_frame_size: u32) -> io::Result<IpcServer<C>> {
+        let mut listener = Listener::bind(address)?;
 
         let poll = Poll::new()?;
         let events = Events::with_capacity(128);
@@ -45,33 +335,42 @@ impl<C: IpcServerCommand> IpcServer<C> {
             listener,
             poll,
             events,
+            max_frame_size,
+            connections: HashMap::new(),
+            // Token(0) is reserved for the listener.
+            next_token: 1,
             _command: Default::default(),
         })
     }
 
     /// Polls for new messages from any clients, and processes and responds.
+    /// Connections stay open across calls: a client may send any number of
+    /// commands over the same stream, and is only dropped on EOF or error.
     pub fn handle_new_messages<'a>(&mut self, mut context: C::Context<'a>) -> io::Result<()> {
         self.poll.poll(&mut self.events, None)?;
 
-        for event in self.events.iter() {
+        let events = std::mem::replace(&mut self.events, Events::with_capacity(0));
+        for event in events.iter() {
             match event.token() {
                 Token(0) => loop {
                     match self.listener.accept() {
-                        Ok((mut stream, _)) => {
-                            let mut buffer = [0; 1024];
-                            match stream.read(&mut buffer) {
-                                Ok(bytes_read) => {
-                                    let command = bincode::deserialize::<C>(&buffer[..bytes_read])
-                                        .map_err(|e| {
-                                            io::Error::new(io::ErrorKind::InvalidData, e)
-                                        })?;
-                                    self.process_command(command, &mut context, &mut stream)?;
-                                }
-                                Err(err) => {
-                                    eprintln!("Failed to read from connection: {}", err);
-                                    break;
-                                }
-                            }
+                        Ok(mut stream) => {
+                            let token = Token(self.next_token);
+                            self.next_token += 1;
+
+                            self.poll
+                                .registry()
+                                .register(&mut stream, token, Interest::READABLE)?;
+
+                            self.connections.insert(
+                                token,
+                                Connection {
+                                    stream,
+                                    reader: FrameReader::new(self.max_frame_size),
+                                    #[cfg(unix)]
+                                    received_fds: Vec::new(),
+                                },
+                            );
                         }
                         Err(ref err) if would_block(err) => break,
                         Err(err) => {
@@ -80,62 +379,204 @@ impl<C: IpcServerCommand> IpcServer<C> {
                         }
                     }
                 },
-                _ => unreachable!(),
+                token => {
+                    if let Err(err) = self.handle_connection(token, &mut context) {
+                        eprintln!("Dropping connection {:?}: {}", token, err);
+                        self.remove_connection(token);
+                    }
+                }
             }
         }
+        self.events = events;
 
         Ok(())
     }
 
-    #[inline(always)]
-    fn process_command<'a, 'b>(
-        &self,
-        command: C,
-        context: &'b mut C::Context<'a>,
-        stream: &mut UnixStream,
+    #[cfg(unix)]
+    fn handle_connection<'a>(
+        &mut self,
+        token: Token,
+        context: &mut C::Context<'a>,
     ) -> io::Result<()> {
-        let response = command.process(context);
         loop {
-            match bincode::serialize_into(&mut *stream, &response)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            let connection = self
+                .connections
+                .get_mut(&token)
+                .expect("event for unknown connection token");
+
+            let raw_fd = connection.stream.as_raw_fd();
+            let payload = match connection
+                .reader
+                .read_from_fd(raw_fd, &mut connection.received_fds)
             {
-                Ok(()) => return Ok(()),
-                Err(ref err) if would_block(err) => {
-                    // Spin loop is okay here.
-                    // IPC server is not intended for large payloads or high volumes.
-                    std::hint::spin_loop();
-                    continue;
-                }
-                e => return e,
+                Ok(Some(payload)) => payload,
+                Ok(None) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            let (request_id, command) = decode_tagged::<C>(&payload)?;
+            let fds = std::mem::take(&mut connection.received_fds);
+            if fds.is_empty() {
+                let mut sink = StreamSink {
+                    stream: &mut connection.stream,
+                    request_id,
+                };
+                command.process_stream(context, &mut sink)?;
+                ResponseSink::<C::Response>::finish(&mut sink)?;
+            } else {
+                let response = command.process_with_fds(context, fds);
+                let mut sink = StreamSink {
+                    stream: &mut connection.stream,
+                    request_id,
+                };
+                sink.send(response)?;
+                ResponseSink::<C::Response>::finish(&mut sink)?;
             }
         }
     }
+
+    #[cfg(not(unix))]
+    fn handle_connection<'a>(
+        &mut self,
+        token: Token,
+        context: &mut C::Context<'a>,
+    ) -> io::Result<()> {
+        loop {
+            let connection = self
+                .connections
+                .get_mut(&token)
+                .expect("event for unknown connection token");
+
+            let payload = match connection.reader.read_from(&mut connection.stream) {
+                Ok(Some(payload)) => payload,
+                Ok(None) => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            let (request_id, command) = decode_tagged::<C>(&payload)?;
+            let mut sink = StreamSink {
+                stream: &mut connection.stream,
+                request_id,
+            };
+            command.process_stream(context, &mut sink)?;
+            ResponseSink::<C::Response>::finish(&mut sink)?;
+        }
+    }
+
+    fn remove_connection(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+        }
+    }
 }
 
 fn would_block(err: &std::io::Error) -> bool {
     err.kind() == std::io::ErrorKind::WouldBlock
 }
 
-/// Serialize and write the `command` provided to the `UnixStream` at the
-/// `socket_path` provided. If there is an active `IpcServer`, it will receive
+/// Serialize and write the `command` provided to the connection at the
+/// `address` provided. If there is an active `IpcServer`, it will receive
 /// and process this command upon polling.
-pub fn client_send<C: IpcServerCommand>(command: &C, socket_path: &str) {
-    let mut stream = UnixStream::connect(socket_path).unwrap();
-    bincode::serialize_into(&mut stream, command).unwrap();
+pub fn client_send<C: IpcServerCommand>(command: &C, address: &str) {
+    let mut stream = Stream::connect(address).unwrap();
+    let payload = encode_tagged(0, command).unwrap();
+    write_frame(&mut stream, &payload).unwrap();
     println!("sent command: {:?}", command);
 
+    let mut reader = FrameReader::new(DEFAULT_MAX_FRAME_SIZE);
+    loop {
+        match reader.read_from(&mut stream) {
+            Ok(Some(payload)) => {
+                match decode_tagged::<StreamMessage<C::Response>>(&payload) {
+                    Ok((_, StreamMessage::Item(response))) => {
+                        println!("received response: {:?}", response);
+                    }
+                    Ok((_, StreamMessage::End)) => {}
+                    Err(err) => eprintln!("failed to parse response: {}", err),
+                }
+                return;
+            }
+            Ok(None) => {
+                #[allow(deprecated)]
+                std::thread::sleep_ms(1);
+                continue;
+            }
+            Err(err) => {
+                eprintln!("failed to read response: {} {}", err, err.kind());
+                return;
+            }
+        }
+    }
+}
+
+/// Writes `payload` as a length-prefixed frame over `fd` via `sendmsg`,
+/// attaching `fds` as an `SCM_RIGHTS` control message on the first send.
+/// Loops on partial sends like `write_frame` does on partial `write`s.
+/// Unix only.
+#[cfg(unix)]
+fn write_frame_with_fds(fd: RawFd, payload: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(LEN_PREFIX_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+
+    let mut written = 0;
+    let mut first = true;
+    while written < frame.len() {
+        let iov = [IoSlice::new(&frame[written..])];
+        let cmsgs: &[ControlMessage] = if first {
+            &[ControlMessage::ScmRights(fds)]
+        } else {
+            &[]
+        };
+
+        match sendmsg::<()>(fd, &iov, cmsgs, MsgFlags::empty(), None) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole frame",
+                ))
+            }
+            Ok(n) => {
+                written += n;
+                first = false;
+            }
+            Err(nix::errno::Errno::EAGAIN) => {
+                // Spin loop is okay here.
+                // IPC server is not intended for large payloads or high volumes.
+                std::hint::spin_loop();
+                continue;
+            }
+            Err(errno) => return Err(io::Error::from_raw_os_error(errno as i32)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Like `client_send`, but also hands `fds` to the server via an
+/// `SCM_RIGHTS` ancillary message. At most 16 file descriptors can be
+/// attached to a single command; see `recvmsg_with_fds`. Unix only.
+#[cfg(unix)]
+pub fn client_send_with_fds<C: IpcServerCommand>(command: &C, address: &str, fds: &[RawFd]) {
+    let mut stream = Stream::connect(address).unwrap();
+    let payload = encode_tagged(0, command).unwrap();
+    write_frame_with_fds(stream.as_raw_fd(), &payload, fds).unwrap();
+    println!("sent command with {} fd(s): {:?}", fds.len(), command);
+
+    let mut reader = FrameReader::new(DEFAULT_MAX_FRAME_SIZE);
     loop {
-        let mut buffer = [0; 1024];
-        match stream.read(&mut buffer) {
-            Ok(bytes_read) => {
-                if let Ok(response) = bincode::deserialize::<C::Response>(&buffer[..bytes_read]) {
-                    println!("received response: {:?}", response);
-                } else {
-                    eprintln!("failed to parse response: {:?}", &buffer[..bytes_read]);
+        match reader.read_from(&mut stream) {
+            Ok(Some(payload)) => {
+                match decode_tagged::<StreamMessage<C::Response>>(&payload) {
+                    Ok((_, StreamMessage::Item(response))) => {
+                        println!("received response: {:?}", response);
+                    }
+                    Ok((_, StreamMessage::End)) => {}
+                    Err(err) => eprintln!("failed to parse response: {}", err),
                 }
                 return;
             }
-            Err(ref err) if would_block(&err) => {
+            Ok(None) => {
                 #[allow(deprecated)]
                 std::thread::sleep_ms(1);
                 continue;
@@ -147,3 +588,387 @@ pub fn client_send<C: IpcServerCommand>(command: &C, socket_path: &str) {
         }
     }
 }
+
+/// A connection to a subscribed command, yielding the responses the server
+/// streams back one at a time. Returned by `client_subscribe`.
+pub struct IpcSubscription<R> {
+    stream: Stream,
+    reader: FrameReader,
+    _response: PhantomData<R>,
+}
+
+impl<R: for<'a> Deserialize<'a> + std::fmt::Debug> Iterator for IpcSubscription<R> {
+    type Item = io::Result<R>;
+
+    /// Blocks until the next streamed response arrives, or returns `None`
+    /// once the server has signaled the stream is finished. A `Some(Err)`
+    /// here means the connection broke before that signal arrived, or a
+    /// received frame could not be decoded.
+    fn next(&mut self) -> Option<io::Result<R>> {
+        loop {
+            match self.reader.read_from(&mut self.stream) {
+                Ok(Some(payload)) => {
+                    let message = match decode_tagged::<StreamMessage<R>>(&payload) {
+                        Ok((_, message)) => message,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    return match message {
+                        StreamMessage::Item(response) => Some(Ok(response)),
+                        StreamMessage::End => None,
+                    };
+                }
+                Ok(None) => {
+                    #[allow(deprecated)]
+                    std::thread::sleep_ms(1);
+                    continue;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Serialize and write `command` to the connection at `address`, then
+/// return an `IpcSubscription` that lazily yields each streamed response.
+pub fn client_subscribe<C: IpcServerCommand>(
+    command: &C,
+    address: &str,
+) -> io::Result<IpcSubscription<C::Response>> {
+    let mut stream = Stream::connect(address)?;
+    let payload = encode_tagged(0, command)?;
+    write_frame(&mut stream, &payload)?;
+
+    Ok(IpcSubscription {
+        stream,
+        reader: FrameReader::new(DEFAULT_MAX_FRAME_SIZE),
+        _response: PhantomData,
+    })
+}
+
+/// A long-lived client connection that can have several commands
+/// outstanding at once. Each command sent through `send` is tagged with a
+/// monotonically increasing request id; `recv` matches responses to their
+/// waiter by that id, regardless of arrival order, buffering any others
+/// until asked for. For commands that stream several responses, call
+/// `recv` once per expected response; `client_subscribe`/`IpcSubscription`
+/// is the dedicated API for that.
+pub struct IpcConnection<C: IpcServerCommand> {
+    stream: Stream,
+    reader: FrameReader,
+    next_id: u64,
+    pending: HashMap<u64, VecDeque<C::Response>>,
+}
+
+impl<C: IpcServerCommand> IpcConnection<C> {
+    /// Connects to the `IpcServer` listening at `address`.
+    pub fn connect(address: &str) -> io::Result<Self> {
+        Ok(IpcConnection {
+            stream: Stream::connect(address)?,
+            reader: FrameReader::new(DEFAULT_MAX_FRAME_SIZE),
+            next_id: 0,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Serializes and writes `command`, returning the request id it was
+    /// tagged with so the matching response can later be retrieved with
+    /// `recv`. Does not wait for a response, so further commands can be
+    /// sent before this one's response arrives.
+    pub fn send(&mut self, command: &C) -> io::Result<u64> {
+        let request_id = self.next_id;
+        self.next_id += 1;
+
+        let payload = encode_tagged(request_id, command)?;
+        write_frame(&mut self.stream, &payload)?;
+        Ok(request_id)
+    }
+
+    /// Blocks until a response tagged with `request_id` has arrived.
+    /// Responses to other outstanding requests that arrive first are
+    /// buffered and returned by a later call to `recv` with their id.
+    pub fn recv(&mut self, request_id: u64) -> io::Result<C::Response> {
+        loop {
+            if let Some(queue) = self.pending.get_mut(&request_id) {
+                if let Some(response) = queue.pop_front() {
+                    if queue.is_empty() {
+                        self.pending.remove(&request_id);
+                    }
+                    return Ok(response);
+                }
+            }
+
+            match self.reader.read_from(&mut self.stream) {
+                Ok(Some(payload)) => {
+                    let (received_id, message) = decode_tagged::<StreamMessage<C::Response>>(&payload)?;
+                    if let StreamMessage::Item(response) = message {
+                        self.pending.entry(received_id).or_default().push_back(response);
+                    }
+                }
+                Ok(None) => {
+                    #[allow(deprecated)]
+                    std::thread::sleep_ms(1);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    /// A `Read` that hands back the pre-scripted chunks (or errors) in
+    /// order, one per call, so a frame split across several `WouldBlock`
+    /// wakeups can be simulated without a real socket. A chunk larger than
+    /// the caller's buffer is only partially consumed, with the remainder
+    /// left at the front of the queue for the next call, matching how a
+    /// real `Read` impl behaves.
+    struct ChunkedReader {
+        chunks: VecDeque<io::Result<Vec<u8>>>,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.chunks.pop_front() {
+                Some(Ok(mut bytes)) => {
+                    let n = bytes.len().min(buf.len());
+                    buf[..n].copy_from_slice(&bytes[..n]);
+                    if n < bytes.len() {
+                        self.chunks.push_front(Ok(bytes.split_off(n)));
+                    }
+                    Ok(n)
+                }
+                Some(Err(err)) => Err(err),
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn would_block_err() -> io::Error {
+        io::Error::new(ErrorKind::WouldBlock, "would block")
+    }
+
+    #[test]
+    fn frame_reader_reassembles_across_would_block() {
+        let payload = b"hello frame reader".to_vec();
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+
+        let mut stream = ChunkedReader {
+            chunks: VecDeque::from(vec![
+                Ok(frame[..2].to_vec()),
+                Err(would_block_err()),
+                Ok(frame[2..6].to_vec()),
+                Err(would_block_err()),
+                Ok(frame[6..].to_vec()),
+            ]),
+        };
+
+        let mut reader = FrameReader::new(DEFAULT_MAX_FRAME_SIZE);
+        let mut would_blocks = 0;
+        let got = loop {
+            match reader.read_from(&mut stream).unwrap() {
+                Some(payload) => break payload,
+                None => would_blocks += 1,
+            }
+        };
+        assert_eq!(got, payload);
+        assert!(would_blocks > 0, "expected at least one WouldBlock along the way");
+    }
+
+    #[test]
+    fn frame_reader_rejects_frame_over_max_size() {
+        let mut stream = ChunkedReader {
+            chunks: VecDeque::from(vec![Ok(100u32.to_le_bytes().to_vec())]),
+        };
+
+        let mut reader = FrameReader::new(4);
+        let err = reader.read_from(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct EchoCommand(u64);
+
+    impl IpcServerCommand for EchoCommand {
+        type Response = u64;
+        type Context<'a> = ();
+
+        fn process<'a, 'b>(self, _context: &'b mut Self::Context<'a>) -> u64 {
+            self.0
+        }
+    }
+
+    /// A command whose `process_stream` sends several responses instead of
+    /// the single one `process` would, for exercising `ResponseSink`'s
+    /// streaming path and `StreamMessage::End`.
+    #[derive(Serialize, Deserialize, Debug)]
+    struct CountdownCommand(u64);
+
+    impl IpcServerCommand for CountdownCommand {
+        type Response = u64;
+        type Context<'a> = ();
+
+        fn process<'a, 'b>(self, _context: &'b mut Self::Context<'a>) -> u64 {
+            self.0
+        }
+
+        fn process_stream<'a, 'b>(
+            self,
+            _context: &'b mut Self::Context<'a>,
+            sink: &mut dyn ResponseSink<u64>,
+        ) -> io::Result<()> {
+            for n in (0..self.0).rev() {
+                sink.send(n)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// `IpcConnection::recv` must match each response to the request id it
+    /// was asked for, even when responses are asked for in a different
+    /// order than they arrive in.
+    #[cfg(unix)]
+    #[test]
+    fn ipc_connection_matches_out_of_order_responses() {
+        let address = format!("/tmp/ipc-server-test-{}.sock", std::process::id());
+
+        let mut server = IpcServer::<EchoCommand>::new(&address).unwrap();
+        std::thread::spawn(move || loop {
+            server.handle_new_messages(()).ok();
+        });
+
+        let mut conn = IpcConnection::<EchoCommand>::connect(&address).unwrap();
+        let id_a = conn.send(&EchoCommand(111)).unwrap();
+        let id_b = conn.send(&EchoCommand(222)).unwrap();
+
+        // Ask for the second response first; recv must not hand back the
+        // first response just because it arrives first.
+        assert_eq!(conn.recv(id_b).unwrap(), 222);
+        assert_eq!(conn.recv(id_a).unwrap(), 111);
+
+        let _ = std::fs::remove_file(&address);
+    }
+
+    /// Two concurrent clients must be accepted onto distinct tokens, a
+    /// command from one must be answered on its own connection, and
+    /// dropping the other must remove only that connection.
+    #[cfg(unix)]
+    #[test]
+    fn server_dispatches_to_distinct_tokens_and_drops_on_disconnect() {
+        let address = format!("/tmp/ipc-server-test-multi-{}.sock", std::process::id());
+
+        let mut server = IpcServer::<EchoCommand>::new(&address).unwrap();
+
+        let mut client_a = IpcConnection::<EchoCommand>::connect(&address).unwrap();
+        let client_b = IpcConnection::<EchoCommand>::connect(&address).unwrap();
+
+        // The listener's accept loop drains the backlog until WouldBlock,
+        // so one poll cycle accepts both waiting connections.
+        server.handle_new_messages(()).unwrap();
+        assert_eq!(server.connections.len(), 2);
+
+        let id_a = client_a.send(&EchoCommand(111)).unwrap();
+        server.handle_new_messages(()).unwrap();
+        assert_eq!(client_a.recv(id_a).unwrap(), 111);
+        assert_eq!(server.connections.len(), 2);
+
+        drop(client_b);
+        server.handle_new_messages(()).unwrap();
+        assert_eq!(server.connections.len(), 1);
+
+        let _ = std::fs::remove_file(&address);
+    }
+
+    /// `process_stream` sending several responses for one command must
+    /// reach the client as that many `IpcSubscription` items, with the
+    /// trailing `StreamMessage::End` ending iteration rather than appearing
+    /// as a response.
+    #[cfg(unix)]
+    #[test]
+    fn client_subscribe_streams_responses_then_ends() {
+        let address = format!("/tmp/ipc-server-test-stream-{}.sock", std::process::id());
+
+        let mut server = IpcServer::<CountdownCommand>::new(&address).unwrap();
+        std::thread::spawn(move || loop {
+            server.handle_new_messages(()).ok();
+        });
+
+        let subscription = client_subscribe(&CountdownCommand(3), &address).unwrap();
+        let responses: Vec<u64> = subscription.map(|r| r.unwrap()).collect();
+        assert_eq!(responses, vec![2, 1, 0]);
+
+        let _ = std::fs::remove_file(&address);
+    }
+
+    /// A command whose `process_with_fds` reads from a received file
+    /// descriptor, to prove the descriptor handed to it is live and
+    /// readable rather than just a number copied through the payload.
+    #[cfg(unix)]
+    #[derive(Serialize, Deserialize, Debug)]
+    struct ReadFdCommand;
+
+    #[cfg(unix)]
+    impl IpcServerCommand for ReadFdCommand {
+        type Response = Vec<u8>;
+        type Context<'a> = ();
+
+        fn process<'a, 'b>(self, _context: &'b mut Self::Context<'a>) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn process_with_fds<'a, 'b>(
+            self,
+            _context: &'b mut Self::Context<'a>,
+            mut fds: Vec<OwnedFd>,
+        ) -> Vec<u8> {
+            let mut file = std::fs::File::from(fds.pop().expect("expected one fd"));
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf).unwrap();
+            buf
+        }
+    }
+
+    /// A file descriptor attached with `write_frame_with_fds`, the way
+    /// `client_send_with_fds` sends it, must arrive at `process_with_fds`
+    /// as a usable, readable descriptor, not just bytes copied through the
+    /// control message.
+    #[cfg(unix)]
+    #[test]
+    fn client_send_with_fds_delivers_a_usable_fd() {
+        use std::os::fd::AsRawFd;
+
+        let address = format!("/tmp/ipc-server-test-fds-{}.sock", std::process::id());
+
+        let mut server = IpcServer::<ReadFdCommand>::new(&address).unwrap();
+        std::thread::spawn(move || loop {
+            server.handle_new_messages(()).ok();
+        });
+
+        let (read_fd, write_fd) = nix::unistd::pipe().unwrap();
+        nix::unistd::write(&write_fd, b"hello through an fd").unwrap();
+        drop(write_fd);
+
+        let mut stream = Stream::connect(&address).unwrap();
+        let payload = encode_tagged(0, &ReadFdCommand).unwrap();
+        write_frame_with_fds(stream.as_raw_fd(), &payload, &[read_fd.as_raw_fd()]).unwrap();
+        drop(read_fd);
+
+        let mut reader = FrameReader::new(DEFAULT_MAX_FRAME_SIZE);
+        let response = loop {
+            if let Some(payload) = reader.read_from(&mut stream).unwrap() {
+                break payload;
+            }
+        };
+        let (_, message) = decode_tagged::<StreamMessage<Vec<u8>>>(&response).unwrap();
+        match message {
+            StreamMessage::Item(bytes) => assert_eq!(bytes, b"hello through an fd"),
+            StreamMessage::End => panic!("expected a response item before End"),
+        }
+
+        let _ = std::fs::remove_file(&address);
+    }
+}
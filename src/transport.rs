@@ -0,0 +1,263 @@
+//! Platform transport: a `Listener`/`Stream` pair backed by a Unix domain
+//! socket on Unix and a named pipe on Windows, each wrapped so the rest of
+//! the crate sees the same `read`/`write`/poll-registration surface and
+//! never has to branch on platform. Framing and serialization are
+//! identical across both, so an `IpcServerCommand` implementation is
+//! portable unchanged.
+
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+use std::io::{self, Read, Write};
+
+#[cfg(unix)]
+mod platform {
+    use super::*;
+    use mio::net::{UnixListener, UnixStream};
+    use std::fs::{remove_file, set_permissions, Permissions};
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    /// Binds a Unix domain socket at the filesystem path given, restricted
+    /// to owner read/write.
+    pub struct Listener(UnixListener);
+
+    pub struct Stream(pub(crate) UnixStream);
+
+    impl Listener {
+        pub fn bind(path: &str) -> io::Result<Listener> {
+            if Path::new(path).exists() {
+                remove_file(path)?;
+            }
+
+            let listener = UnixListener::bind(path)?;
+            set_permissions(path, Permissions::from_mode(0o600))?;
+            Ok(Listener(listener))
+        }
+
+        pub fn accept(&self) -> io::Result<Stream> {
+            self.0.accept().map(|(stream, _)| Stream(stream))
+        }
+    }
+
+    impl Stream {
+        pub fn connect(path: &str) -> io::Result<Stream> {
+            UnixStream::connect(path).map(Stream)
+        }
+
+        pub fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Source for Listener {
+        fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.0.register(registry, token, interests)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.0.reregister(registry, token, interests)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            self.0.deregister(registry)
+        }
+    }
+
+    impl Source for Stream {
+        fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.0.register(registry, token, interests)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.0.reregister(registry, token, interests)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            self.0.deregister(registry)
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::*;
+    use mio::windows::NamedPipe;
+    use std::os::windows::io::FromRawHandle;
+    use std::os::windows::raw::HANDLE;
+
+    /// Win32 `ERROR_PIPE_CONNECTED`: returned by a `ConnectNamedPipe`-style
+    /// call when a client connected between instance creation and the call
+    /// itself, so the connection is already complete.
+    const ERROR_PIPE_CONNECTED: i32 = 535;
+
+    fn would_block(err: &io::Error) -> bool {
+        err.kind() == io::ErrorKind::WouldBlock
+    }
+
+    /// Creates the next pipe instance listening at `address`, the moral
+    /// equivalent of a fresh slot for `UnixListener::accept` to hand out.
+    fn create_instance(address: &str) -> io::Result<NamedPipe> {
+        NamedPipe::new(address)
+    }
+
+    /// Follows mio's documented overlapped-accept pattern for named pipes:
+    /// one `NamedPipe` instance (`waiting`) always has a `ConnectNamedPipe`
+    /// in flight, registered with the `Poll` under the listener's own
+    /// token. A readiness event on that token means the pending connect
+    /// completed, i.e. a client connected to `waiting` — `accept` hands
+    /// that instance back as the `Stream` and immediately creates and
+    /// registers a new waiting instance so the next client always has
+    /// somewhere to connect.
+    pub struct Listener {
+        address: String,
+        waiting: NamedPipe,
+        registration: Option<(Registry, Token, Interest)>,
+    }
+
+    pub struct Stream(pub(crate) NamedPipe);
+
+    impl Listener {
+        pub fn bind(address: &str) -> io::Result<Listener> {
+            // Creating the first pipe instance here makes the address
+            // connectable immediately, the same way `UnixListener::bind`
+            // makes the socket path connectable immediately.
+            let waiting = create_instance(address)?;
+            Ok(Listener {
+                address: address.to_owned(),
+                waiting,
+                registration: None,
+            })
+        }
+
+        /// Begins (or re-begins) waiting for a client to connect to
+        /// `self.waiting`. `Ok(())`/`ERROR_PIPE_CONNECTED` mean a client is
+        /// already connected; `WouldBlock` means the connect is genuinely
+        /// pending and a later readiness event will tell us it's done.
+        fn begin_connect(&mut self) -> io::Result<()> {
+            match self.waiting.connect() {
+                Ok(()) => Ok(()),
+                Err(ref err) if err.raw_os_error() == Some(ERROR_PIPE_CONNECTED) => Ok(()),
+                Err(ref err) if would_block(err) => Ok(()),
+                Err(err) => Err(err),
+            }
+        }
+
+        pub fn accept(&mut self) -> io::Result<Stream> {
+            let (registry, token, interests) = self
+                .registration
+                .as_ref()
+                .map(|(registry, token, interests)| {
+                    (
+                        registry
+                            .try_clone()
+                            .expect("failed to clone Poll registry"),
+                        *token,
+                        *interests,
+                    )
+                })
+                .expect("Listener::accept called before it was registered with a Poll");
+
+            // The readiness event that led here means `waiting`'s pending
+            // connect has completed. Swap in a fresh instance so the next
+            // client has something to connect to, and hand the connected
+            // one back as the accepted `Stream`.
+            let mut next = create_instance(&self.address)?;
+            registry.register(&mut next, token, interests)?;
+            let mut connected = std::mem::replace(&mut self.waiting, next);
+            self.begin_connect()?;
+
+            // `connected` is still registered under the listener's own
+            // token; `IpcServer` is about to register it again under a
+            // fresh per-connection token, which `Source::register` only
+            // allows once an existing registration has been removed.
+            connected.deregister(&registry)?;
+
+            Ok(Stream(connected))
+        }
+    }
+
+    impl Stream {
+        pub fn connect(address: &str) -> io::Result<Stream> {
+            // SAFETY: `CreateFileW` returns a handle we uniquely own.
+            let handle = unsafe { raw_connect(address)? };
+            Ok(Stream(unsafe { NamedPipe::from_raw_handle(handle) }))
+        }
+    }
+
+    unsafe fn raw_connect(address: &str) -> io::Result<HANDLE> {
+        // Opens the client end of the named pipe at `address`, retrying
+        // while the server has not yet called `accept` and all instances
+        // are busy, the same way a Unix client blocks in `connect` until
+        // the server is listening.
+        miow::pipe::NamedPipe::connect(address).map(|p| p.into_raw_handle() as HANDLE)
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Source for Listener {
+        fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.waiting.register(registry, token, interests)?;
+            self.registration = Some((registry.try_clone()?, token, interests));
+            self.begin_connect()
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.waiting.reregister(registry, token, interests)?;
+            self.registration = Some((registry.try_clone()?, token, interests));
+            Ok(())
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            self.waiting.deregister(registry)?;
+            self.registration = None;
+            Ok(())
+        }
+    }
+
+    impl Source for Stream {
+        fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.0.register(registry, token, interests)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.0.reregister(registry, token, interests)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            self.0.deregister(registry)
+        }
+    }
+}
+
+pub use platform::{Listener, Stream};